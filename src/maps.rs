@@ -1,15 +1,20 @@
-use std::sync::Arc;
-use std::sync::mpsc::{self, Sender, Receiver};
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver};
 use std::cmp::Ordering;
 use std::collections::BinaryHeap;
 use std::iter::IntoIterator;
 
 use crossbeam::{Scope, ScopedJoinHandle};
 
+/// The payload of a panicking closure, as caught by `catch_unwind`.
+pub type PanicPayload = Box<Any + Send>;
+
 struct Packet<T> {
     // this should be unique for a given instance of `*ParMap`
     idx: usize,
-    data: Option<T>,
+    data: Result<T, PanicPayload>,
 }
 
 impl<T> PartialOrd for Packet<T> {
@@ -37,24 +42,36 @@ impl<T: Send> Iterator for UnorderedParMap<T> {
 
     fn next(&mut self) -> Option<(usize, T)> {
         match self.rx.recv() {
-            Ok(Packet { data: Some(x), idx }) => Some((idx, x)),
-            Ok(Packet { data: None, .. }) => {
-                panic!("simple_parallel::unordered_map: closure panicked")
-            }
+            Ok(Packet { data: Ok(x), idx }) => Some((idx, x)),
+            Ok(Packet { data: Err(payload), .. }) => panic::resume_unwind(payload),
             Err(mpsc::RecvError) => None,
         }
     }
 }
 
-struct Panicker<T: Send> {
-    tx: Sender<Packet<T>>,
-    idx: usize,
-    all_ok: bool
+impl<T: Send> UnorderedParMap<T> {
+    /// Adapt this iterator so that a closure panic is reported as an
+    /// `Err` instead of being re-raised (via `resume_unwind`) on the
+    /// thread that reads this iterator.
+    pub fn results(self) -> UnorderedParMapResults<T> {
+        UnorderedParMapResults { inner: self }
+    }
 }
-impl<T: Send> Drop for Panicker<T> {
-    fn drop(&mut self) {
-        if !self.all_ok {
-            let _ = self.tx.send(Packet { idx: self.idx, data: None });
+
+/// Like `UnorderedParMap`, but surfaces closure panics as `Err`
+/// values instead of unwinding. Constructed by calling
+/// `UnorderedParMap::results`.
+pub struct UnorderedParMapResults<T: Send> {
+    inner: UnorderedParMap<T>,
+}
+
+impl<T: Send> Iterator for UnorderedParMapResults<T> {
+    type Item = (usize, Result<T, PanicPayload>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.inner.rx.recv() {
+            Ok(Packet { data, idx }) => Some((idx, data)),
+            Err(mpsc::RecvError) => None,
         }
     }
 }
@@ -76,10 +93,8 @@ pub fn unordered_map<'a, I: IntoIterator, F, T>(scope: &Scope<'a>, iter: I, f: F
         let f = f.clone();
 
         scope.spawn(move || {
-            let mut p = Panicker { tx: tx, idx: idx, all_ok: false };
-            let val = f(elem);
-            let _ = p.tx.send(Packet { idx: idx, data: Some(val) });
-            p.all_ok = true;
+            let data = panic::catch_unwind(AssertUnwindSafe(|| f(elem)));
+            let _ = tx.send(Packet { idx: idx, data: data });
         })
     }).collect();
 
@@ -107,8 +122,8 @@ impl<T: Send> Iterator for ParMap<T> {
                 let packet = self.queue.pop().unwrap();
                 self.looking_for += 1;
                 match packet.data {
-                    Some(x) => return Some(x),
-                    None => panic!("simple_parallel::map: closure panicked")
+                    Ok(x) => return Some(x),
+                    Err(payload) => panic::resume_unwind(payload),
                 }
             }
             match self.unordered.rx.recv() {
@@ -123,6 +138,39 @@ impl<T: Send> Iterator for ParMap<T> {
     }
 }
 
+impl<T: Send> ParMap<T> {
+    /// Adapt this iterator so that a closure panic is reported as an
+    /// `Err` instead of being re-raised (via `resume_unwind`) on the
+    /// thread that reads this iterator.
+    pub fn results(self) -> ParMapResults<T> {
+        ParMapResults { inner: self }
+    }
+}
+
+/// Like `ParMap`, but surfaces closure panics as `Err` values instead
+/// of unwinding. Constructed by calling `ParMap::results`.
+pub struct ParMapResults<T: Send> {
+    inner: ParMap<T>,
+}
+
+impl<T: Send> Iterator for ParMapResults<T> {
+    type Item = Result<T, PanicPayload>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.inner.queue.peek().map_or(false, |x| x.idx == self.inner.looking_for) {
+                let packet = self.inner.queue.pop().unwrap();
+                self.inner.looking_for += 1;
+                return Some(packet.data);
+            }
+            match self.inner.unordered.rx.recv() {
+                Ok(packet) => self.inner.queue.push(packet),
+                Err(mpsc::RecvError) => return None,
+            }
+        }
+    }
+}
+
 /// Execute `f` on `iter`, yielding the results in the order the
 /// correspond to in `iter`.
 ///
@@ -140,3 +188,362 @@ pub fn map<'a, I: IntoIterator, F, T>(scope: &Scope<'a>, iter: I, f: F) -> ParMa
         queue: BinaryHeap::new(),
     }
 }
+
+/// Execute `f` on each element in `iter`, with unspecified yield
+/// order, using a fixed pool of `threads` worker threads.
+///
+/// Unlike `unordered_map`, this does not spawn a thread per element
+/// of `iter`. Instead, a single feeder thread drains `iter` into a
+/// bounded channel of capacity `buffer`, so the feeder blocks once
+/// `buffer` elements are waiting to be picked up (backpressure), and
+/// `threads` persistent workers pull from that channel and compute
+/// `f`. Results go back to the returned iterator over a channel that
+/// is bounded by `buffer` too, so a worker blocks while `buffer`
+/// results are unread. Peak memory is therefore bounded by roughly
+/// `2 * buffer + threads` outstanding elements and results, rather
+/// than the size of the whole input, which makes this usable on very
+/// long or infinite iterators.
+///
+/// A worker stops as soon as it notices that the consumer has dropped
+/// the returned iterator (its next attempt to send a result fails).
+/// Once every worker has stopped, the shared end of the work channel
+/// is gone, so the feeder's next (possibly blocked) push to it fails
+/// too and the feeder stops draining `iter`.
+pub fn unordered_map_with_pool<'a, I, F, T>(scope: &Scope<'a>, threads: usize, buffer: usize, iter: I, f: F)
+    -> UnorderedParMap<T>
+    where I: IntoIterator + Send + 'a,
+          I::Item: Send + 'a,
+          F: 'a + Send + Sync + Fn(I::Item) -> T,
+          T: Send + 'a
+{
+    let threads = if threads == 0 { 1 } else { threads };
+    let (tx, rx) = mpsc::sync_channel(buffer);
+    let (work_tx, work_rx) = mpsc::sync_channel::<(usize, I::Item)>(buffer);
+    let f = Arc::new(f);
+    let work_rx = Arc::new(Mutex::new(work_rx));
+
+    let mut guards = Vec::with_capacity(threads + 1);
+
+    guards.push(scope.spawn(move || {
+        for item in iter.into_iter().enumerate() {
+            if work_tx.send(item).is_err() {
+                break;
+            }
+        }
+    }));
+
+    for _ in 0..threads {
+        let tx = tx.clone();
+        let f = f.clone();
+        let work_rx = work_rx.clone();
+        guards.push(scope.spawn(move || {
+            loop {
+                let next = work_rx.lock().unwrap().recv();
+                let (idx, elem) = match next {
+                    Ok(item) => item,
+                    Err(_) => break,
+                };
+                let data = panic::catch_unwind(AssertUnwindSafe(|| f(elem)));
+                if tx.send(Packet { idx: idx, data: data }).is_err() {
+                    break;
+                }
+            }
+        }));
+    }
+
+    UnorderedParMap {
+        rx: rx,
+        _guards: guards,
+    }
+}
+
+/// Execute `f` on `iter`, yielding the results in the order they
+/// correspond to in `iter`, using a fixed pool of `threads` worker
+/// threads.
+///
+/// This is like `map`, but bounded: see `unordered_map_with_pool` for
+/// how `threads` and `buffer` control memory use. Results that finish
+/// ahead of the next one in order are held by the returned `ParMap`
+/// until that one arrives; this reorder queue is not bounded by
+/// `threads` or `buffer`.
+pub fn map_with_pool<'a, I, F, T>(scope: &Scope<'a>, threads: usize, buffer: usize, iter: I, f: F) -> ParMap<T>
+    where I: IntoIterator + Send + 'a,
+          I::Item: Send + 'a,
+          F: 'a + Send + Sync + Fn(I::Item) -> T,
+          T: Send + 'a
+{
+    ParMap {
+        unordered: unordered_map_with_pool(scope, threads, buffer, iter, f),
+        looking_for: 0,
+        queue: BinaryHeap::new(),
+    }
+}
+
+/// Execute `f` on each element in `iter`, with unspecified yield
+/// order, using `threads` worker threads that pull elements from
+/// `iter` on demand.
+///
+/// Unlike `unordered_map`, this does not eagerly consume `iter` up
+/// front by spawning a thread per element. Instead `iter` is wrapped
+/// in an `Arc<Mutex<_>>` shared by `threads` worker threads; each
+/// worker locks it only long enough to pull the next `(idx, item)`
+/// pair before releasing the lock and computing `f` concurrently with
+/// the others. At most `threads` elements are being mapped at once.
+/// Results are sent over an unbounded channel, so while the returned
+/// iterator is alive, workers keep pulling from `iter` ahead of what
+/// the consumer has read. A worker stops pulling from `iter` as soon
+/// as it notices that the consumer has dropped the returned iterator
+/// (its next attempt to send a result fails), so a long or infinite
+/// `iter` is not drained any further after that.
+pub fn unordered_map_lazy<'a, I, F, T>(scope: &Scope<'a>, threads: usize, iter: I, f: F) -> UnorderedParMap<T>
+    where I: IntoIterator + 'a,
+          I::IntoIter: Send + 'a,
+          I::Item: Send + 'a,
+          F: 'a + Send + Sync + Fn(I::Item) -> T,
+          T: Send + 'a
+{
+    let threads = if threads == 0 { 1 } else { threads };
+    let (tx, rx) = mpsc::channel();
+    let f = Arc::new(f);
+    let iter = Arc::new(Mutex::new(iter.into_iter().enumerate()));
+
+    let guards = (0..threads).map(|_| {
+        let tx = tx.clone();
+        let f = f.clone();
+        let iter = iter.clone();
+
+        scope.spawn(move || {
+            loop {
+                let next = iter.lock().unwrap().next();
+                let (idx, elem) = match next {
+                    Some(item) => item,
+                    None => break,
+                };
+
+                let data = panic::catch_unwind(AssertUnwindSafe(|| f(elem)));
+                if tx.send(Packet { idx: idx, data: data }).is_err() {
+                    break;
+                }
+            }
+        })
+    }).collect();
+
+    UnorderedParMap {
+        rx: rx,
+        _guards: guards,
+    }
+}
+
+/// Execute `f` on `iter`, yielding the results in the order they
+/// correspond to in `iter`, using `threads` worker threads that pull
+/// elements from `iter` on demand.
+///
+/// This is like `map`, but lazy: see `unordered_map_lazy` for how
+/// `iter` is shared between workers.
+pub fn map_lazy<'a, I, F, T>(scope: &Scope<'a>, threads: usize, iter: I, f: F) -> ParMap<T>
+    where I: IntoIterator + 'a,
+          I::IntoIter: Send + 'a,
+          I::Item: Send + 'a,
+          F: 'a + Send + Sync + Fn(I::Item) -> T,
+          T: Send + 'a
+{
+    ParMap {
+        unordered: unordered_map_lazy(scope, threads, iter, f),
+        looking_for: 0,
+        queue: BinaryHeap::new(),
+    }
+}
+
+/// Execute `map_op` on each element of `iter` and combine the results
+/// with `reduce_op`, starting from `identity`, using `threads` worker
+/// threads.
+///
+/// `iter` is collected and split into at most `threads` contiguous
+/// chunks of equal length (the last may be shorter); each chunk is
+/// folded by its own worker thread into a partial result (`map_op`
+/// applied to each element, combined into that worker's accumulator
+/// with `reduce_op`, starting from a clone of `identity`), so the
+/// expensive `map_op`/`reduce_op` work happens in parallel instead of
+/// in a single serial fold over a prior `collect`. The partials are
+/// then combined with `reduce_op` left-to-right, in the
+/// same order as their chunks in `iter`, so `reduce_op` only needs to
+/// be associative, not commutative: operations like string
+/// concatenation, where order matters, are well defined, not just
+/// commutative ones like `sum` or `max`.
+///
+/// If `map_op` or `reduce_op` panics in a worker, the original panic
+/// payload is caught there and re-raised (via `resume_unwind`) on the
+/// calling thread once its chunk is joined, the same as `map` and
+/// `unordered_map` do.
+pub fn reduce<'a, I, F, R, T>(scope: &Scope<'a>, threads: usize, iter: I, identity: T, map_op: F, reduce_op: R) -> T
+    where I: IntoIterator + 'a,
+          I::Item: Send + 'a,
+          F: 'a + Send + Sync + Fn(I::Item) -> T,
+          R: 'a + Send + Sync + Fn(T, T) -> T,
+          T: Clone + Send + 'a
+{
+    let threads = if threads == 0 { 1 } else { threads };
+    let map_op = Arc::new(map_op);
+    let reduce_op = Arc::new(reduce_op);
+
+    let mut rest: Vec<I::Item> = iter.into_iter().collect();
+    let chunk_len = (rest.len() + threads - 1) / threads;
+
+    let mut chunks = Vec::with_capacity(threads);
+    while !rest.is_empty() {
+        let tail = if chunk_len < rest.len() { rest.split_off(chunk_len) } else { Vec::new() };
+        chunks.push(rest);
+        rest = tail;
+    }
+
+    let guards: Vec<_> = chunks.into_iter().map(|chunk| {
+        let map_op = map_op.clone();
+        let reduce_op = reduce_op.clone();
+        let identity = identity.clone();
+
+        scope.spawn(move || {
+            panic::catch_unwind(AssertUnwindSafe(|| {
+                chunk.into_iter().fold(identity, |acc, elem| reduce_op(acc, map_op(elem)))
+            }))
+        })
+    }).collect();
+
+    guards.into_iter().fold(identity, |acc, guard| {
+        match guard.join() {
+            Ok(partial) => reduce_op(acc, partial),
+            Err(payload) => panic::resume_unwind(payload),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn with_pool_computes_all_elements() {
+        crossbeam::scope(|scope| {
+            let mut v: Vec<_> = unordered_map_with_pool(scope, 4, 2, 0..100, |x| x * 2).collect();
+            v.sort();
+            assert_eq!(v, (0..100).map(|x| (x, x * 2)).collect::<Vec<_>>());
+
+            let v: Vec<_> = map_with_pool(scope, 4, 2, 0..100, |x| x * 2).collect();
+            assert_eq!(v, (0..100).map(|x| x * 2).collect::<Vec<_>>());
+        });
+    }
+
+    #[test]
+    fn with_pool_stops_feeding_when_consumer_drops_early() {
+        // regression test: with an infinite `iter` and a small buffer,
+        // both the feeder and the workers must notice the consumer
+        // dropped the returned iterator, or this test (and the
+        // enclosing `crossbeam::scope`) hangs forever.
+        crossbeam::scope(|scope| {
+            let mut it = unordered_map_with_pool(scope, 4, 2, 0.., |x| x);
+            it.next();
+            it.next();
+            drop(it);
+        });
+    }
+
+    #[test]
+    fn lazy_computes_all_elements() {
+        crossbeam::scope(|scope| {
+            let mut v: Vec<_> = unordered_map_lazy(scope, 4, 0..100, |x| x * 2).collect();
+            v.sort();
+            assert_eq!(v, (0..100).map(|x| (x, x * 2)).collect::<Vec<_>>());
+
+            let v: Vec<_> = map_lazy(scope, 4, 0..100, |x| x * 2).collect();
+            assert_eq!(v, (0..100).map(|x| x * 2).collect::<Vec<_>>());
+        });
+    }
+
+    #[test]
+    fn lazy_stops_pulling_when_consumer_drops_early() {
+        // regression test: with an infinite `iter`, workers must stop
+        // pulling once the consumer drops the returned iterator, or
+        // this test (and the enclosing `crossbeam::scope`) hangs forever.
+        crossbeam::scope(|scope| {
+            let mut it = unordered_map_lazy(scope, 4, 0.., |x| x);
+            it.next();
+            it.next();
+            drop(it);
+        });
+    }
+
+    #[test]
+    fn map_resumes_original_panic_payload() {
+        crossbeam::scope(|scope| {
+            let mut it = map(scope, 0..4, |x| {
+                if x == 2 { panic!("boom-{}", x) } else { x }
+            });
+            let caught = panic::catch_unwind(AssertUnwindSafe(|| while it.next().is_some() {}));
+            let payload = caught.unwrap_err();
+            assert_eq!(payload.downcast_ref::<String>().map(|s| s.as_str()), Some("boom-2"));
+        });
+    }
+
+    #[test]
+    fn unordered_map_resumes_original_panic_payload() {
+        crossbeam::scope(|scope| {
+            let mut it = unordered_map(scope, 0..4, |x| {
+                if x == 2 { panic!("boom-{}", x) } else { x }
+            });
+            let caught = panic::catch_unwind(AssertUnwindSafe(|| while it.next().is_some() {}));
+            let payload = caught.unwrap_err();
+            assert_eq!(payload.downcast_ref::<String>().map(|s| s.as_str()), Some("boom-2"));
+        });
+    }
+
+    #[test]
+    fn results_reports_panic_as_err_instead_of_unwinding() {
+        crossbeam::scope(|scope| {
+            let results: Vec<_> = map(scope, 0..4, |x| {
+                if x == 2 { panic!("boom-{}", x) } else { x }
+            }).results().collect();
+
+            assert_eq!(results.len(), 4);
+            for (idx, result) in results.into_iter().enumerate() {
+                match result {
+                    Ok(x) => assert_eq!(x, idx),
+                    Err(payload) => {
+                        assert_eq!(idx, 2);
+                        assert_eq!(payload.downcast_ref::<String>().map(|s| s.as_str()), Some("boom-2"));
+                    }
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn reduce_sums_in_parallel() {
+        crossbeam::scope(|scope| {
+            let sum = reduce(scope, 4, 0..100, 0, |x| x * 2, |a, b| a + b);
+            assert_eq!(sum, (0..100).map(|x| x * 2).sum());
+        });
+    }
+
+    #[test]
+    fn reduce_preserves_order_for_noncommutative_op() {
+        crossbeam::scope(|scope| {
+            let words = vec!["a", "b", "c", "d", "e"];
+            let joined = reduce(scope, 3, words.clone(), String::new(),
+                                 |s| s.to_string(),
+                                 |a, b| a + b.as_str());
+            assert_eq!(joined, words.concat());
+        });
+    }
+
+    #[test]
+    fn reduce_resumes_original_panic_payload() {
+        crossbeam::scope(|scope| {
+            let caught = panic::catch_unwind(AssertUnwindSafe(|| {
+                reduce(scope, 4, 0..100, 0, |x| {
+                    if x == 42 { panic!("boom-{}", x) } else { x }
+                }, |a, b| a + b)
+            }));
+            let payload = caught.unwrap_err();
+            assert_eq!(payload.downcast_ref::<String>().map(|s| s.as_str()), Some("boom-42"));
+        });
+    }
+}